@@ -3,23 +3,187 @@ use dict::VALUES_JSON;
 use serde::{Deserialize, Serialize};
 
 use std::collections::HashMap;
+use std::process::Stdio;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::process::{Child, ChildStderr, ChildStdin, ChildStdout, Command as ProcessCommand};
 use tokio::sync::RwLock;
+use tokio::time::timeout;
 use tower_lsp::jsonrpc::Result;
 use tower_lsp::lsp_types::*;
 use tower_lsp::{Client, LanguageServer, LspService, Server};
 
+const SAPF_BIN: &str = "sapf";
+
 #[derive(Deserialize, Serialize, Debug)]
 pub struct CategoryData {
     pub description: String,
-    pub items: HashMap<String, String>,
+    pub items: HashMap<String, ItemData>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct ItemData {
+    pub description: String,
+    /// Stack effect, e.g. `a b -- a+b`, shown as an inlay hint after the word.
+    pub signature: Option<String>,
+}
+
+impl<'de> Deserialize<'de> for ItemData {
+    /// Accepts both the current `{ description, signature }` shape and the
+    /// older plain-description-string shape, so dictionary entries that
+    /// haven't been migrated to carry a `signature` yet don't fail to parse.
+    fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Raw {
+            Description(String),
+            Full {
+                description: String,
+                #[serde(default)]
+                signature: Option<String>,
+            },
+        }
+
+        Ok(match Raw::deserialize(deserializer)? {
+            Raw::Description(description) => ItemData {
+                description,
+                signature: None,
+            },
+            Raw::Full {
+                description,
+                signature,
+            } => ItemData {
+                description,
+                signature,
+            },
+        })
+    }
+}
+
+struct SapfInterpreter {
+    child: Child,
+    stdin: ChildStdin,
+    stdout: BufReader<ChildStdout>,
+    stderr: BufReader<ChildStderr>,
+}
+
+impl std::fmt::Debug for SapfInterpreter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SapfInterpreter").finish_non_exhaustive()
+    }
+}
+
+impl SapfInterpreter {
+    fn spawn() -> std::io::Result<Self> {
+        let mut child = ProcessCommand::new(SAPF_BIN)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()?;
+
+        let stdin = child.stdin.take().expect("sapf child missing stdin");
+        let stdout = BufReader::new(child.stdout.take().expect("sapf child missing stdout"));
+        let stderr = BufReader::new(child.stderr.take().expect("sapf child missing stderr"));
+
+        Ok(Self {
+            child,
+            stdin,
+            stdout,
+            stderr,
+        })
+    }
+
+    fn is_alive(&mut self) -> bool {
+        matches!(self.child.try_wait(), Ok(None))
+    }
+
+    /// Sends `expr` to the interpreter and collects whatever it prints within
+    /// a fixed idle window.
+    ///
+    /// `stdout`/`stderr` are buffered readers that persist across calls, so a
+    /// slow-running `expr` (plausible for audio synthesis) can still finish
+    /// after this call's window closes; its output would otherwise sit in the
+    /// buffer and get attributed to the *next* `eval()` instead. To avoid that
+    /// misattribution, any output still buffered from a previous call is
+    /// drained and reported separately via `EvalOutput::stale_lines` before
+    /// `expr` is even sent, rather than being mixed into this call's result.
+    async fn eval(&mut self, expr: &str) -> std::io::Result<EvalOutput> {
+        let stale_lines = read_available_lines(&mut self.stdout, Duration::ZERO).await.len()
+            + read_available_lines(&mut self.stderr, Duration::ZERO).await.len();
+
+        self.stdin.write_all(expr.as_bytes()).await?;
+        self.stdin.write_all(b"\n").await?;
+        self.stdin.flush().await?;
+
+        let window = Duration::from_millis(200);
+        let stdout = read_available_lines(&mut self.stdout, window).await;
+        let stderr = read_available_lines(&mut self.stderr, window).await;
+
+        Ok(EvalOutput {
+            stdout,
+            stderr,
+            stale_lines,
+        })
+    }
+}
+
+/// Result of [`SapfInterpreter::eval`].
+struct EvalOutput {
+    stdout: Vec<String>,
+    stderr: Vec<String>,
+    /// Number of lines that were sitting in the buffers from a previous,
+    /// slower-than-its-window evaluation and were discarded rather than
+    /// attributed to this one.
+    stale_lines: usize,
+}
+
+/// Reads lines from `reader` until `window` passes with no new data.
+///
+/// This drains whatever is sitting in the reader's buffer, not necessarily
+/// just the output of the most recent write - see the caveat on `eval`.
+async fn read_available_lines<R: AsyncBufRead + Unpin>(
+    reader: &mut R,
+    window: Duration,
+) -> Vec<String> {
+    let mut lines = Vec::new();
+
+    loop {
+        let mut line = String::new();
+        match timeout(window, reader.read_line(&mut line)).await {
+            Ok(Ok(n)) if n > 0 => lines.push(line.trim_end().to_string()),
+            _ => break,
+        }
+    }
+
+    lines
 }
 
+/// A document's text together with its line-start index, kept in sync so
+/// LSP position conversions don't rescan the whole document on every edit.
+#[derive(Debug, Default)]
+struct Document {
+    text: String,
+    index: LineIndex,
+}
+
+/// The last computed tokens for a document, keyed by the `resultId` handed
+/// back to the client so a later delta request can be matched to them.
+type TokenCacheEntry = (String, Vec<SemanticToken>);
+
 #[derive(Debug)]
 struct Backend {
     client: Client,
-    documents: Arc<RwLock<HashMap<Url, String>>>,
+    documents: Arc<RwLock<HashMap<Url, Document>>>,
     categories: HashMap<String, CategoryData>,
+    interpreter: Arc<RwLock<Option<SapfInterpreter>>>,
+    token_cache: Arc<RwLock<HashMap<Url, TokenCacheEntry>>>,
+    next_result_id: AtomicU64,
 }
 
 impl Backend {
@@ -28,20 +192,254 @@ impl Backend {
             client,
             documents: Arc::new(RwLock::new(HashMap::new())),
             categories: load_categories(),
+            interpreter: Arc::new(RwLock::new(None)),
+            token_cache: Arc::new(RwLock::new(HashMap::new())),
+            next_result_id: AtomicU64::new(0),
         }
     }
 
+    fn next_token_result_id(&self) -> String {
+        self.next_result_id
+            .fetch_add(1, Ordering::Relaxed)
+            .to_string()
+    }
+
     async fn get_document_content(&self, uri: &Url) -> Option<String> {
-        self.documents.read().await.get(uri).cloned()
+        self.documents.read().await.get(uri).map(|d| d.text.clone())
     }
 
-    fn get_all_keywords(&self) -> HashMap<String, String> {
+    async fn eval_in_interpreter(&self, uri: Url, content: &str, range: Range, expr: &str) {
+        let mut guard = self.interpreter.write().await;
+
+        if !guard.as_mut().is_some_and(SapfInterpreter::is_alive) {
+            match SapfInterpreter::spawn() {
+                Ok(interpreter) => *guard = Some(interpreter),
+                Err(err) => {
+                    drop(guard);
+                    self.client
+                        .log_message(MessageType::ERROR, format!("failed to restart sapf: {err}"))
+                        .await;
+                    return;
+                }
+            }
+        }
+
+        let Some(interpreter) = guard.as_mut() else {
+            return;
+        };
+
+        let eval_result = interpreter.eval(expr).await;
+        drop(guard);
+
+        let EvalOutput {
+            stdout: stdout_lines,
+            stderr: stderr_lines,
+            stale_lines,
+        } = match eval_result {
+            Ok(output) => output,
+            Err(err) => {
+                self.client
+                    .log_message(MessageType::ERROR, format!("sapf eval failed: {err}"))
+                    .await;
+                return;
+            }
+        };
+
+        if stale_lines > 0 {
+            self.client
+                .log_message(
+                    MessageType::WARNING,
+                    format!(
+                        "discarded {stale_lines} line(s) of output from a previous, \
+                         slower-than-expected evaluation"
+                    ),
+                )
+                .await;
+        }
+
+        if !stdout_lines.is_empty() {
+            self.client
+                .show_message(MessageType::INFO, stdout_lines.join("\n"))
+                .await;
+        }
+
+        let mut diagnostics = self.compute_diagnostics(content);
+        diagnostics.extend(stderr_lines.into_iter().map(|line| Diagnostic {
+            range,
+            severity: Some(DiagnosticSeverity::ERROR),
+            source: Some("sapf".to_string()),
+            message: line,
+            ..Default::default()
+        }));
+        self.client.publish_diagnostics(uri, diagnostics, None).await;
+    }
+
+    fn get_all_keywords(&self) -> HashMap<String, ItemData> {
         let mut all_keywords = HashMap::new();
         for category in self.categories.values() {
             all_keywords.extend(category.items.clone());
         }
         all_keywords
     }
+
+    async fn publish_diagnostics_for(&self, uri: Url, content: &str) {
+        let diagnostics = self.compute_diagnostics(content);
+        self.client.publish_diagnostics(uri, diagnostics, None).await;
+    }
+
+    fn compute_diagnostics(&self, content: &str) -> Vec<Diagnostic> {
+        let all_keywords = self.get_all_keywords();
+        let local_bindings = collect_local_bindings(content);
+
+        let mut diagnostics = Vec::new();
+        let mut open_stack: Vec<(char, Position)> = Vec::new();
+
+        for (line_num, line) in content.lines().enumerate() {
+            let line_num = line_num as u32;
+            let mut col: u32 = 0;
+            let mut chars = line.chars().peekable();
+
+            while let Some(c) = chars.next() {
+                match c {
+                    '(' | '[' | '{' => {
+                        open_stack.push((c, Position::new(line_num, col)));
+                        col += 1;
+                    }
+
+                    ')' | ']' | '}' => {
+                        let expected_open = matching_open(c);
+                        match open_stack.last() {
+                            Some((open_c, _)) if *open_c == expected_open => {
+                                open_stack.pop();
+                            }
+                            _ => {
+                                diagnostics.push(unbalanced_diagnostic(Range::new(
+                                    Position::new(line_num, col),
+                                    Position::new(line_num, col + 1),
+                                )));
+                            }
+                        }
+                        col += 1;
+                    }
+
+                    c if c.is_alphabetic() => {
+                        let start_col = col;
+                        let mut word = String::new();
+                        word.push(c);
+                        col += 1;
+                        while let Some(&next_c) = chars.peek() {
+                            if next_c.is_alphanumeric() || next_c == '_' {
+                                word.push(next_c);
+                                chars.next();
+                                col += 1;
+                            } else {
+                                break;
+                            }
+                        }
+
+                        // `category.item` is a valid, category-qualified word (see
+                        // `completion`), not two bare words joined by a dot -
+                        // consume the `.item` part too so `category` alone isn't
+                        // flagged as unknown.
+                        let mut qualified_item = None;
+                        if chars.peek() == Some(&'.') && self.categories.contains_key(&word) {
+                            let mut rest = chars.clone();
+                            rest.next();
+                            if rest.peek().is_some_and(|c| c.is_alphabetic()) {
+                                chars.next();
+                                col += 1;
+                                let mut item = String::new();
+                                while let Some(&next_c) = chars.peek() {
+                                    if next_c.is_alphanumeric() || next_c == '_' {
+                                        item.push(next_c);
+                                        chars.next();
+                                        col += 1;
+                                    } else {
+                                        break;
+                                    }
+                                }
+                                qualified_item = Some(item);
+                            }
+                        }
+
+                        let is_known = match &qualified_item {
+                            Some(item) => self
+                                .categories
+                                .get(&word)
+                                .is_some_and(|category| category.items.contains_key(item)),
+                            None => {
+                                all_keywords.contains_key(&word) || local_bindings.contains(&word)
+                            }
+                        };
+
+                        if !is_known {
+                            let full_word = match &qualified_item {
+                                Some(item) => format!("{word}.{item}"),
+                                None => word.clone(),
+                            };
+                            diagnostics.push(Diagnostic {
+                                range: Range::new(
+                                    Position::new(line_num, start_col),
+                                    Position::new(line_num, col),
+                                ),
+                                severity: Some(DiagnosticSeverity::WARNING),
+                                source: Some("sapf-lsp".to_string()),
+                                message: format!("unknown word `{full_word}`"),
+                                ..Default::default()
+                            });
+                        }
+                    }
+
+                    _ => {
+                        col += 1;
+                    }
+                }
+            }
+        }
+
+        for (_, open_pos) in open_stack {
+            diagnostics.push(unbalanced_diagnostic(Range::new(
+                open_pos,
+                Position::new(open_pos.line, open_pos.character + 1),
+            )));
+        }
+
+        diagnostics
+    }
+}
+
+fn matching_open(close: char) -> char {
+    match close {
+        ')' => '(',
+        ']' => '[',
+        '}' => '{',
+        _ => unreachable!("matching_open called with non-closing delimiter"),
+    }
+}
+
+fn unbalanced_diagnostic(range: Range) -> Diagnostic {
+    Diagnostic {
+        range,
+        severity: Some(DiagnosticSeverity::ERROR),
+        source: Some("sapf-lsp".to_string()),
+        message: "unbalanced delimiter".to_string(),
+        ..Default::default()
+    }
+}
+
+fn collect_local_bindings(content: &str) -> std::collections::HashSet<String> {
+    let mut bindings = std::collections::HashSet::new();
+    let mut words = content.split_whitespace().peekable();
+
+    while let Some(word) = words.next() {
+        if word == ":" {
+            if let Some(name) = words.peek() {
+                bindings.insert(name.trim_end_matches(';').to_string());
+            }
+        }
+    }
+
+    bindings
 }
 
 fn load_categories() -> HashMap<String, CategoryData> {
@@ -89,8 +487,17 @@ impl LanguageServer for Backend {
                     ..Default::default()
                 }),
                 text_document_sync: Some(TextDocumentSyncCapability::Kind(
-                    TextDocumentSyncKind::FULL,
+                    TextDocumentSyncKind::INCREMENTAL,
                 )),
+                execute_command_provider: Some(ExecuteCommandOptions {
+                    commands: vec![
+                        "sapf.evalSelection".to_string(),
+                        "sapf.evalLine".to_string(),
+                    ],
+                    work_done_progress_options: WorkDoneProgressOptions::default(),
+                }),
+                inlay_hint_provider: Some(OneOf::Left(true)),
+                code_action_provider: Some(CodeActionProviderCapability::Simple(true)),
                 ..Default::default()
             },
             ..Default::default()
@@ -98,29 +505,79 @@ impl LanguageServer for Backend {
     }
 
     async fn initialized(&self, _: InitializedParams) {
+        match SapfInterpreter::spawn() {
+            Ok(interpreter) => {
+                *self.interpreter.write().await = Some(interpreter);
+            }
+            Err(err) => {
+                self.client
+                    .log_message(MessageType::ERROR, format!("failed to spawn sapf: {err}"))
+                    .await;
+            }
+        }
+
         self.client
             .log_message(MessageType::INFO, "Server initialized!")
             .await;
     }
 
+    async fn execute_command(
+        &self,
+        params: ExecuteCommandParams,
+    ) -> Result<Option<serde_json::Value>> {
+        let Some((uri, requested_range)) = parse_eval_arguments(&params.arguments) else {
+            return Ok(None);
+        };
+
+        let Some(content) = self.get_document_content(&uri).await else {
+            return Ok(None);
+        };
+
+        let range = match params.command.as_str() {
+            "sapf.evalSelection" => requested_range,
+            "sapf.evalLine" => whole_line_range(&content, requested_range.start.line),
+            _ => return Ok(None),
+        };
+
+        let Some(expr) = extract_range(&content, range) else {
+            return Ok(None);
+        };
+
+        self.eval_in_interpreter(uri, &content, range, &expr).await;
+        Ok(None)
+    }
+
     async fn shutdown(&self) -> Result<()> {
         Ok(())
     }
 
     async fn did_open(&self, params: DidOpenTextDocumentParams) {
-        self.documents
-            .write()
-            .await
-            .insert(params.text_document.uri, params.text_document.text);
+        let uri = params.text_document.uri;
+        let text = params.text_document.text;
+        let index = LineIndex::new(&text);
+        self.documents.write().await.insert(
+            uri.clone(),
+            Document {
+                text: text.clone(),
+                index,
+            },
+        );
+        self.publish_diagnostics_for(uri, &text).await;
     }
 
     async fn did_change(&self, params: DidChangeTextDocumentParams) {
-        if let Some(change) = params.content_changes.last() {
-            self.documents
-                .write()
-                .await
-                .insert(params.text_document.uri, change.text.clone());
-        }
+        let uri = params.text_document.uri;
+        let content = {
+            let mut documents = self.documents.write().await;
+            let document = documents.entry(uri.clone()).or_default();
+
+            for change in params.content_changes {
+                apply_content_change(document, change);
+            }
+
+            document.text.clone()
+        };
+        self.publish_diagnostics_for(uri, &content).await;
     }
 
     async fn hover(&self, params: HoverParams) -> Result<Option<Hover>> {
@@ -147,7 +604,9 @@ impl LanguageServer for Backend {
                 let all_keywords = self.get_all_keywords();
                 if let Some(doc) = all_keywords.get(word) {
                     return Ok(Some(Hover {
-                        contents: HoverContents::Scalar(MarkedString::String(doc.clone())),
+                        contents: HoverContents::Scalar(MarkedString::String(
+                            doc.description.clone(),
+                        )),
                         range: None,
                     }));
                 }
@@ -196,7 +655,9 @@ impl LanguageServer for Backend {
                                 .map(|(k, d)| CompletionItem {
                                     label: k.clone(),
                                     kind: Some(CompletionItemKind::KEYWORD),
-                                    documentation: Some(Documentation::String(d.clone())),
+                                    documentation: Some(Documentation::String(
+                                        d.description.clone(),
+                                    )),
                                     insert_text: Some(k.clone()),
                                     ..Default::default()
                                 }),
@@ -211,7 +672,9 @@ impl LanguageServer for Backend {
                             .map(|(k, d)| CompletionItem {
                                 label: k.clone(),
                                 kind: Some(CompletionItemKind::KEYWORD),
-                                documentation: Some(Documentation::String(d.clone())),
+                                documentation: Some(Documentation::String(
+                                    d.description.clone(),
+                                )),
                                 insert_text: Some(k.clone()),
                                 ..Default::default()
                             }),
@@ -230,106 +693,495 @@ impl LanguageServer for Backend {
         let uri = params.text_document.uri;
 
         if let Some(content) = self.get_document_content(&uri).await {
-            let mut tokens = Vec::new();
             let all_keywords = self.get_all_keywords();
+            let tokens = compute_semantic_tokens(&content, &all_keywords);
 
-            for (line_num, line) in content.lines().enumerate() {
-                let mut offset: u32 = 0;
-
-                let mut chars = line.chars().peekable();
-                while let Some(c) = chars.next() {
-                    match c {
-                        '+' | '-' | '*' | '/' | '=' => {
-                            tokens.push(SemanticToken {
-                                delta_line: line_num as u32,
-                                delta_start: offset,
-                                length: 1,
-                                token_type: 1,
-                                token_modifiers_bitset: 0,
-                            });
-                            offset += 1;
-                        }
+            let result_id = self.next_token_result_id();
+            self.token_cache
+                .write()
+                .await
+                .insert(uri, (result_id.clone(), tokens.clone()));
 
-                        c if c.is_ascii_digit() => {
-                            let mut length: u32 = 1;
-                            while let Some(&next_c) = chars.peek() {
-                                if next_c.is_ascii_digit() || next_c == '.' {
-                                    length += 1;
-                                    chars.next();
-                                } else {
-                                    break;
-                                }
-                            }
-                            tokens.push(SemanticToken {
-                                delta_line: line_num as u32,
-                                delta_start: offset,
-                                length,
-                                token_type: 2,
-                                token_modifiers_bitset: 0,
-                            });
-                            offset += length;
-                        }
+            return Ok(Some(SemanticTokensResult::Tokens(SemanticTokens {
+                result_id: Some(result_id),
+                data: tokens,
+            })));
+        }
 
-                        c if c.is_alphabetic() => {
-                            let mut word = String::new();
-                            word.push(c);
-                            while let Some(&next_c) = chars.peek() {
-                                if next_c.is_alphanumeric() || next_c == '_' {
-                                    word.push(next_c);
-                                    chars.next();
-                                } else {
-                                    break;
-                                }
-                            }
-                            if all_keywords.contains_key(&word) {
-                                tokens.push(SemanticToken {
-                                    delta_line: line_num as u32,
-                                    delta_start: offset,
-                                    length: word.len() as u32,
-                                    token_type: 0,
-                                    token_modifiers_bitset: 0,
-                                });
-                            }
-                            offset += word.len() as u32;
-                        }
+        Ok(None)
+    }
+
+    async fn semantic_tokens_full_delta(
+        &self,
+        params: SemanticTokensDeltaParams,
+    ) -> Result<Option<SemanticTokensFullDeltaResult>> {
+        let uri = params.text_document.uri;
+
+        let Some(content) = self.get_document_content(&uri).await else {
+            return Ok(None);
+        };
+
+        let all_keywords = self.get_all_keywords();
+        let new_tokens = compute_semantic_tokens(&content, &all_keywords);
+        let result_id = self.next_token_result_id();
 
-                        _ => {
-                            offset += 1;
+        let previous = self.token_cache.read().await.get(&uri).cloned();
+
+        let response = match previous {
+            Some((prev_id, prev_tokens)) if prev_id == params.previous_result_id => {
+                let edits = diff_semantic_tokens(&prev_tokens, &new_tokens);
+                SemanticTokensFullDeltaResult::TokensDelta(SemanticTokensDelta {
+                    result_id: Some(result_id.clone()),
+                    edits,
+                })
+            }
+            _ => SemanticTokensFullDeltaResult::Tokens(SemanticTokens {
+                result_id: Some(result_id.clone()),
+                data: new_tokens.clone(),
+            }),
+        };
+
+        self.token_cache
+            .write()
+            .await
+            .insert(uri, (result_id, new_tokens));
+
+        Ok(Some(response))
+    }
+
+    async fn inlay_hint(&self, params: InlayHintParams) -> Result<Option<Vec<InlayHint>>> {
+        let uri = params.text_document.uri;
+        let range = params.range;
+
+        let Some(content) = self.get_document_content(&uri).await else {
+            return Ok(None);
+        };
+
+        let all_keywords = self.get_all_keywords();
+        let mut hints = Vec::new();
+
+        for (line_num, line) in content.lines().enumerate() {
+            let line_num = line_num as u32;
+            if line_num < range.start.line || line_num > range.end.line {
+                continue;
+            }
+
+            let mut col: u32 = 0;
+            let mut chars = line.chars().peekable();
+
+            while let Some(c) = chars.next() {
+                if c.is_alphabetic() {
+                    let mut word = String::new();
+                    word.push(c);
+                    col += 1;
+                    while let Some(&next_c) = chars.peek() {
+                        if next_c.is_alphanumeric() || next_c == '_' {
+                            word.push(next_c);
+                            chars.next();
+                            col += 1;
+                        } else {
+                            break;
                         }
                     }
+
+                    if let Some(signature) =
+                        all_keywords.get(&word).and_then(|item| item.signature.as_ref())
+                    {
+                        hints.push(InlayHint {
+                            position: Position::new(line_num, col),
+                            label: InlayHintLabel::String(signature.clone()),
+                            kind: Some(InlayHintKind::TYPE),
+                            text_edits: None,
+                            tooltip: None,
+                            padding_left: Some(true),
+                            padding_right: None,
+                            data: None,
+                        });
+                    }
+                } else {
+                    col += 1;
                 }
             }
+        }
+
+        Ok(Some(hints))
+    }
+
+    async fn code_action(&self, params: CodeActionParams) -> Result<Option<CodeActionResponse>> {
+        let uri = params.text_document.uri;
+        let range = params.range;
+
+        let Some(content) = self.get_document_content(&uri).await else {
+            return Ok(None);
+        };
+
+        let mut actions = Vec::new();
+
+        for diagnostic in &params.context.diagnostics {
+            if let Some(word) = unknown_word_from_diagnostic(diagnostic) {
+                actions.push(CodeActionOrCommand::CodeAction(define_word_action(
+                    &uri,
+                    &word,
+                    diagnostic.clone(),
+                )));
+            }
+        }
 
-            let mut absolute_tokens = Vec::new();
-            let mut current_line = 0;
-            let mut current_start = 0;
+        if range.start != range.end {
+            if let Some(selected) = extract_range(&content, range) {
+                let selected = selected.trim();
+                if !selected.is_empty() {
+                    let mut existing = collect_local_bindings(&content);
+                    existing.extend(self.get_all_keywords().into_keys());
+                    let name = fresh_word_name(&existing);
 
-            for token in tokens {
-                if token.delta_line == current_line {
-                    absolute_tokens.push(SemanticToken {
-                        delta_line: 0,
-                        delta_start: token.delta_start - current_start,
-                        ..token
+                    actions.push(CodeActionOrCommand::CodeAction(extract_to_word_action(
+                        &uri, range, selected, &name,
+                    )));
+                }
+            }
+        }
+
+        Ok(Some(actions))
+    }
+}
+
+fn unknown_word_from_diagnostic(diagnostic: &Diagnostic) -> Option<String> {
+    // Only our own diagnostics carry this source, and of those only the
+    // unknown-word ones quote a word in backticks; raw "sapf" interpreter
+    // stderr can contain backtick-quoted text for other reasons.
+    if diagnostic.source.as_deref() != Some("sapf-lsp") {
+        return None;
+    }
+    let message = &diagnostic.message;
+    let start = message.find('`')? + 1;
+    let end = start + message[start..].find('`')?;
+    Some(message[start..end].to_string())
+}
+
+fn fresh_word_name(existing: &std::collections::HashSet<String>) -> String {
+    let mut n = 1;
+    loop {
+        let candidate = format!("extracted{n}");
+        if !existing.contains(&candidate) {
+            return candidate;
+        }
+        n += 1;
+    }
+}
+
+fn define_word_action(uri: &Url, word: &str, diagnostic: Diagnostic) -> CodeAction {
+    let edit = TextEdit {
+        range: Range::new(Position::new(0, 0), Position::new(0, 0)),
+        new_text: format!(": {word} ;\n"),
+    };
+
+    let mut changes = HashMap::new();
+    changes.insert(uri.clone(), vec![edit]);
+
+    CodeAction {
+        title: format!("Define word `{word}`"),
+        kind: Some(CodeActionKind::QUICKFIX),
+        diagnostics: Some(vec![diagnostic]),
+        edit: Some(WorkspaceEdit {
+            changes: Some(changes),
+            document_changes: None,
+            change_annotations: None,
+        }),
+        command: None,
+        is_preferred: Some(true),
+        disabled: None,
+        data: None,
+    }
+}
+
+fn extract_to_word_action(uri: &Url, range: Range, selected: &str, name: &str) -> CodeAction {
+    let define_edit = TextEdit {
+        range: Range::new(Position::new(0, 0), Position::new(0, 0)),
+        new_text: format!(": {name} {selected} ;\n"),
+    };
+    let replace_edit = TextEdit {
+        range,
+        new_text: name.to_string(),
+    };
+
+    let mut changes = HashMap::new();
+    changes.insert(uri.clone(), vec![define_edit, replace_edit]);
+
+    CodeAction {
+        title: format!("Extract to named word `{name}`"),
+        kind: Some(CodeActionKind::REFACTOR_EXTRACT),
+        diagnostics: None,
+        edit: Some(WorkspaceEdit {
+            changes: Some(changes),
+            document_changes: None,
+            change_annotations: None,
+        }),
+        command: None,
+        is_preferred: None,
+        disabled: None,
+        data: None,
+    }
+}
+
+#[derive(Debug)]
+struct LineIndex {
+    line_starts: Vec<usize>,
+}
+
+impl Default for LineIndex {
+    fn default() -> Self {
+        Self::new("")
+    }
+}
+
+impl LineIndex {
+    fn new(text: &str) -> Self {
+        let mut line_starts = vec![0];
+        for (i, c) in text.char_indices() {
+            if c == '\n' {
+                line_starts.push(i + 1);
+            }
+        }
+        Self { line_starts }
+    }
+
+    /// Converts an LSP `Position` (UTF-16 code units) into a byte offset into `text`.
+    fn offset(&self, text: &str, position: Position) -> usize {
+        let line_start = match self.line_starts.get(position.line as usize) {
+            Some(&start) => start,
+            None => return text.len(),
+        };
+        let line_end = self
+            .line_starts
+            .get(position.line as usize + 1)
+            .map(|&s| s - 1)
+            .unwrap_or(text.len());
+        let line = &text[line_start..line_end.max(line_start)];
+
+        let mut utf16_units = 0usize;
+        for (byte_offset, c) in line.char_indices() {
+            if utf16_units >= position.character as usize {
+                return line_start + byte_offset;
+            }
+            utf16_units += c.len_utf16();
+        }
+        line_start + line.len()
+    }
+
+    fn line_of_byte_offset(&self, offset: usize) -> usize {
+        match self.line_starts.binary_search(&offset) {
+            Ok(line) => line,
+            Err(insert_at) => insert_at - 1,
+        }
+    }
+
+    /// Patches `line_starts` in place for a `[start, end)` byte-range edit,
+    /// so callers don't need to rescan the whole document after each change.
+    fn apply_edit(&mut self, start: usize, end: usize, new_text: &str) {
+        let start_line = self.line_of_byte_offset(start);
+        let end_line = self.line_of_byte_offset(end);
+        let delta = new_text.len() as isize - (end - start) as isize;
+
+        let inserted_starts: Vec<usize> = new_text
+            .bytes()
+            .enumerate()
+            .filter(|&(_, b)| b == b'\n')
+            .map(|(i, _)| start + i + 1)
+            .collect();
+
+        self.line_starts.drain(start_line + 1..=end_line);
+        for line_start in self.line_starts.iter_mut().skip(start_line + 1) {
+            *line_start = (*line_start as isize + delta) as usize;
+        }
+        self.line_starts
+            .splice(start_line + 1..start_line + 1, inserted_starts);
+    }
+}
+
+fn apply_content_change(document: &mut Document, change: TextDocumentContentChangeEvent) {
+    match change.range {
+        Some(range) => {
+            let start = document.index.offset(&document.text, range.start);
+            let end = document.index.offset(&document.text, range.end);
+            document.index.apply_edit(start, end, &change.text);
+            document.text.replace_range(start..end, &change.text);
+        }
+        None => {
+            document.text = change.text;
+            document.index = LineIndex::new(&document.text);
+        }
+    }
+}
+
+fn compute_semantic_tokens(
+    content: &str,
+    all_keywords: &HashMap<String, ItemData>,
+) -> Vec<SemanticToken> {
+    let mut tokens = Vec::new();
+
+    for (line_num, line) in content.lines().enumerate() {
+        let mut offset: u32 = 0;
+
+        let mut chars = line.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '+' | '-' | '*' | '/' | '=' => {
+                    tokens.push(SemanticToken {
+                        delta_line: line_num as u32,
+                        delta_start: offset,
+                        length: 1,
+                        token_type: 1,
+                        token_modifiers_bitset: 0,
                     });
-                } else {
-                    absolute_tokens.push(SemanticToken {
-                        delta_line: token.delta_line - current_line,
-                        delta_start: token.delta_start,
-                        ..token
+                    offset += 1;
+                }
+
+                c if c.is_ascii_digit() => {
+                    let mut length: u32 = 1;
+                    while let Some(&next_c) = chars.peek() {
+                        if next_c.is_ascii_digit() || next_c == '.' {
+                            length += 1;
+                            chars.next();
+                        } else {
+                            break;
+                        }
+                    }
+                    tokens.push(SemanticToken {
+                        delta_line: line_num as u32,
+                        delta_start: offset,
+                        length,
+                        token_type: 2,
+                        token_modifiers_bitset: 0,
                     });
+                    offset += length;
+                }
+
+                c if c.is_alphabetic() => {
+                    let mut word = String::new();
+                    word.push(c);
+                    while let Some(&next_c) = chars.peek() {
+                        if next_c.is_alphanumeric() || next_c == '_' {
+                            word.push(next_c);
+                            chars.next();
+                        } else {
+                            break;
+                        }
+                    }
+                    if all_keywords.contains_key(&word) {
+                        tokens.push(SemanticToken {
+                            delta_line: line_num as u32,
+                            delta_start: offset,
+                            length: word.len() as u32,
+                            token_type: 0,
+                            token_modifiers_bitset: 0,
+                        });
+                    }
+                    offset += word.len() as u32;
+                }
+
+                _ => {
+                    offset += 1;
                 }
-                current_line = token.delta_line;
-                current_start = token.delta_start;
             }
+        }
+    }
 
-            return Ok(Some(SemanticTokensResult::Tokens(SemanticTokens {
-                result_id: None,
-                data: absolute_tokens,
-            })));
+    let mut absolute_tokens = Vec::new();
+    let mut current_line = 0;
+    let mut current_start = 0;
+
+    for token in tokens {
+        if token.delta_line == current_line {
+            absolute_tokens.push(SemanticToken {
+                delta_line: 0,
+                delta_start: token.delta_start - current_start,
+                ..token
+            });
+        } else {
+            absolute_tokens.push(SemanticToken {
+                delta_line: token.delta_line - current_line,
+                delta_start: token.delta_start,
+                ..token
+            });
         }
+        current_line = token.delta_line;
+        current_start = token.delta_start;
+    }
 
-        Ok(None)
+    absolute_tokens
+}
+
+/// Number of flat `u32` wire values one `SemanticToken` occupies (see the LSP
+/// "semantic tokens" spec and `SemanticToken::serialize_tokens` in `lsp-types`).
+const SEMANTIC_TOKEN_WIDTH: u32 = 5;
+
+fn diff_semantic_tokens(old: &[SemanticToken], new: &[SemanticToken]) -> Vec<SemanticTokensEdit> {
+    // Diff whole tokens, not their flattened u32 fields: `SemanticTokensEdit::data`
+    // only round-trips a `Vec<SemanticToken>` (always a multiple of 5 on the
+    // wire), and a token's fields aren't independently meaningful anyway since
+    // `delta_line`/`delta_start` encode a position relative to the previous token.
+    let common_prefix = old
+        .iter()
+        .zip(new.iter())
+        .take_while(|(a, b)| a == b)
+        .count();
+
+    let old_rest = &old[common_prefix..];
+    let new_rest = &new[common_prefix..];
+
+    let common_suffix = old_rest
+        .iter()
+        .rev()
+        .zip(new_rest.iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+
+    let delete_count = old_rest.len() - common_suffix;
+    let insert_count = new_rest.len() - common_suffix;
+
+    if delete_count == 0 && insert_count == 0 {
+        return Vec::new();
+    }
+
+    vec![SemanticTokensEdit {
+        start: common_prefix as u32 * SEMANTIC_TOKEN_WIDTH,
+        delete_count: delete_count as u32 * SEMANTIC_TOKEN_WIDTH,
+        data: Some(new_rest[..insert_count].to_vec()),
+    }]
+}
+
+fn parse_eval_arguments(arguments: &[serde_json::Value]) -> Option<(Url, Range)> {
+    #[derive(Deserialize)]
+    struct EvalArgs {
+        uri: Url,
+        range: Range,
+    }
+
+    let args: EvalArgs = serde_json::from_value(arguments.first()?.clone()).ok()?;
+    Some((args.uri, args.range))
+}
+
+fn whole_line_range(content: &str, line: u32) -> Range {
+    let length = content
+        .lines()
+        .nth(line as usize)
+        .map(|l| l.encode_utf16().count() as u32)
+        .unwrap_or(0);
+    Range::new(Position::new(line, 0), Position::new(line, length))
+}
+
+fn extract_range(content: &str, range: Range) -> Option<String> {
+    // `range` carries UTF-16 code-unit offsets per the LSP spec, so we must go
+    // through `LineIndex` rather than indexing `content` with them directly -
+    // a raw byte index can land mid-codepoint and panic on multi-byte lines.
+    let index = LineIndex::new(content);
+    let start = index.offset(content, range.start);
+    let end = index.offset(content, range.end);
+    if start > end || end > content.len() {
+        return None;
     }
+    Some(content[start..end].to_string())
 }
 
 fn get_word_at_position(content: &str, line: usize, character: usize) -> Option<&str> {